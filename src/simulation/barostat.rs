@@ -0,0 +1,191 @@
+/*
+ * Cymbalum, Molecular Simulation in Rust
+ * Copyright (C) 2015 Guillaume Fraux
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/
+*/
+
+//! Berendsen barostat, coupling the system to a pressure bath by
+//! rescaling the `UnitCell` and every particle position towards a
+//! target pressure.
+
+use ::types::*;
+use ::universe::Universe;
+use super::{Pressure, PressureTensor, Compute};
+
+/// Default isothermal compressibility, in inverse units of the
+/// pressure returned by the `Pressure` compute. This is only a starting
+/// point: the Berendsen scheme is not sensitive to the exact value, as
+/// long as `tau_p` dominates the actual relaxation time.
+const DEFAULT_COMPRESSIBILITY: f64 = 4.5e-5;
+
+/// The isotropic Berendsen barostat rescales the whole `UnitCell`, and
+/// every particle position with it, by a single factor `mu` computed
+/// from the instantaneous scalar pressure so that the system relaxes
+/// towards a target pressure `P0` with a time constant `tau_p`:
+///
+/// ```text
+/// mu = [1 - (beta * dt / tau_p) * (P0 - P)] ^ (1/3)
+/// ```
+pub struct Berendsen {
+    /// Target pressure `P0`.
+    target_pressure: f64,
+    /// Isothermal compressibility `beta`.
+    compressibility: f64,
+    /// Coupling time constant `tau_p`.
+    tau_p: f64,
+}
+
+impl Berendsen {
+    /// Create a new `Berendsen` barostat coupling the system to
+    /// `target_pressure`, with a coupling time constant of `tau_p`.
+    pub fn new(target_pressure: f64, tau_p: f64) -> Berendsen {
+        Berendsen {
+            target_pressure: target_pressure,
+            compressibility: DEFAULT_COMPRESSIBILITY,
+            tau_p: tau_p,
+        }
+    }
+
+    /// Use `compressibility` instead of the default isothermal
+    /// compressibility.
+    pub fn with_compressibility(mut self, compressibility: f64) -> Berendsen {
+        self.compressibility = compressibility;
+        return self;
+    }
+
+    fn scaling_factor(&self, universe: &Universe, timestep: f64) -> f64 {
+        let pressure = Pressure.compute(universe);
+        let mu_cubed = 1.0 - (self.compressibility * timestep / self.tau_p) *
+            (self.target_pressure - pressure);
+        return mu_cubed.cbrt();
+    }
+
+    /// Rescale the `UnitCell` and every particle position of `universe`
+    /// by the scaling factor computed from the current pressure, for a
+    /// simulation step of length `timestep`.
+    pub fn apply(&self, universe: &mut Universe, timestep: f64) {
+        let mu = self.scaling_factor(universe, timestep);
+        rescale_cell(universe, Vector3D::new(mu, mu, mu));
+    }
+}
+
+/// The anisotropic variant of the `Berendsen` barostat rescales each
+/// axis of the `UnitCell` independently, from the matching diagonal
+/// component of the `PressureTensor`. This is needed to equilibrate
+/// non-cubic cells, where the pressure is not the same along every
+/// direction.
+pub struct AnisotropicBerendsen {
+    target_pressure: f64,
+    compressibility: f64,
+    tau_p: f64,
+}
+
+impl AnisotropicBerendsen {
+    /// Create a new `AnisotropicBerendsen` barostat coupling the system
+    /// to `target_pressure`, with a coupling time constant of `tau_p`.
+    pub fn new(target_pressure: f64, tau_p: f64) -> AnisotropicBerendsen {
+        AnisotropicBerendsen {
+            target_pressure: target_pressure,
+            compressibility: DEFAULT_COMPRESSIBILITY,
+            tau_p: tau_p,
+        }
+    }
+
+    /// Use `compressibility` instead of the default isothermal
+    /// compressibility.
+    pub fn with_compressibility(mut self, compressibility: f64) -> AnisotropicBerendsen {
+        self.compressibility = compressibility;
+        return self;
+    }
+
+    fn scaling_factors(&self, universe: &Universe, timestep: f64) -> Vector3D {
+        let pressure = PressureTensor.compute(universe);
+        let coupling = self.compressibility * timestep / self.tau_p;
+        return Vector3D::new(
+            (1.0 - coupling * (self.target_pressure - pressure[(0, 0)])).cbrt(),
+            (1.0 - coupling * (self.target_pressure - pressure[(1, 1)])).cbrt(),
+            (1.0 - coupling * (self.target_pressure - pressure[(2, 2)])).cbrt(),
+        );
+    }
+
+    /// Rescale each axis of the `UnitCell` and every particle position
+    /// of `universe` independently, for a simulation step of length
+    /// `timestep`.
+    pub fn apply(&self, universe: &mut Universe, timestep: f64) {
+        let mu = self.scaling_factors(universe, timestep);
+        rescale_cell(universe, mu);
+    }
+}
+
+/// Rescale `universe`'s cell by `mu` along each axis, and move every
+/// particle position along with it so that fractional coordinates are
+/// left unchanged. Positions are scaled in place and are not wrapped
+/// back into the new cell afterwards.
+fn rescale_cell(universe: &mut Universe, mu: Vector3D) {
+    let new_cell = universe.cell().scaled(mu);
+    universe.set_cell(new_cell);
+
+    for i in 0..universe.size() {
+        let position = universe[i].position();
+        let scaled = Vector3D::new(position.x * mu.x, position.y * mu.y, position.z * mu.z);
+        universe[i].set_position(scaled);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::universe::{Universe, Particle, UnitCell};
+    use ::potentials::Harmonic;
+    use ::units;
+
+    const EPS: f64 = 1e-8;
+
+    fn testing_universe() -> Universe {
+        let mut universe = Universe::from_cell(UnitCell::cubic(10.0));
+
+        universe.add_particle(Particle::new("F"));
+        universe[0].set_position(Vector3D::new(0.0, 0.0, 0.0));
+
+        universe.add_particle(Particle::new("F"));
+        universe[1].set_position(Vector3D::new(1.3, 0.0, 0.0));
+
+        universe.add_pair_interaction("F", "F",
+            Harmonic{k: units::from(300.0, "kJ/mol/A^2").unwrap(), x0: units::from(1.2, "A").unwrap()});
+        return universe;
+    }
+
+    #[test]
+    fn berendsen_rescales_cell_and_positions_consistently() {
+        let mut universe = testing_universe();
+        let volume_before = universe.cell().volume();
+        let position_before = universe[1].position();
+
+        let barostat = Berendsen::new(units::from(0.0, "bar").unwrap(), 1000.0);
+        let mu = barostat.scaling_factor(&universe, 1.0);
+        barostat.apply(&mut universe, 1.0);
+
+        assert_approx_eq!(universe.cell().volume(), volume_before * mu.powi(3), EPS);
+        assert_approx_eq!(universe[1].position().x, position_before.x * mu, EPS);
+    }
+
+    #[test]
+    fn anisotropic_berendsen_leaves_axis_at_its_target_pressure_unchanged() {
+        // When the target pressure along x matches the instantaneous
+        // xx component of the pressure tensor, that axis should not be
+        // rescaled at all: mu.x == 1.
+        let mut universe = testing_universe();
+        let pressure_xx = PressureTensor.compute(&universe)[(0, 0)];
+        let position_before = universe[1].position();
+
+        let barostat = AnisotropicBerendsen::new(pressure_xx, 1000.0);
+        let mu = barostat.scaling_factors(&universe, 1.0);
+        barostat.apply(&mut universe, 1.0);
+
+        assert_approx_eq!(mu.x, 1.0, EPS);
+        assert_approx_eq!(universe[1].position().x, position_before.x, EPS);
+    }
+}
@@ -0,0 +1,534 @@
+/*
+ * Cymbalum, Molecular Simulation in Rust
+ * Copyright (C) 2015 Guillaume Fraux
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/
+*/
+
+//! Intramolecular contributions driven by the molecular topology: bond
+//! stretches, angle bends and dihedral torsions. Each contributes
+//! forces distributed over all the participating atoms, and a virial
+//! contribution `sum f.r`, the same way the nonbonded pair potentials
+//! do in `compute.rs`.
+
+use std::collections::VecDeque;
+
+use ::types::*;
+use ::universe::Universe;
+
+/// An angle-bend potential, as a function of the angle `theta` (in
+/// radians) between the two bonds sharing the central atom.
+pub trait AnglePotential {
+    /// Energy of the angle for a value of `theta`.
+    fn energy(&self, theta: f64) -> f64;
+    /// Force factor `-dE/dtheta` for a value of `theta`.
+    fn force(&self, theta: f64) -> f64;
+}
+
+/// A torsion potential, as a function of the dihedral angle `phi` (in
+/// radians) around the central bond of the four participating atoms.
+pub trait DihedralPotential {
+    /// Energy of the dihedral for a value of `phi`.
+    fn energy(&self, phi: f64) -> f64;
+    /// Force factor `-dE/dphi` for a value of `phi`.
+    fn force(&self, phi: f64) -> f64;
+}
+
+/// Which bonded pairs are removed from the nonbonded pair list to avoid
+/// double-counting the same interaction through both the bonded and
+/// nonbonded terms.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExclusionPolicy {
+    /// Exclude no bonded pair: bonds, angles and dihedrals are simply
+    /// added on top of the full nonbonded pair list.
+    None,
+    /// Exclude directly bonded (1-2) pairs only.
+    Pairs12,
+    /// Exclude 1-2 and 1-3 (angle-end) pairs.
+    Pairs13,
+    /// Exclude 1-2, 1-3 and 1-4 (dihedral-end) pairs.
+    Pairs14,
+}
+
+/// The exclusion policy used by `Forces`, `PotentialEnergy` and `Virial`
+/// when deciding whether a nonbonded pair should be skipped in favor of
+/// the bonded terms covering it. 1-3 exclusion (bonds and angle-ends) is
+/// the most common choice for classical force fields, and is used here
+/// as the default.
+pub const DEFAULT_EXCLUSION_POLICY: ExclusionPolicy = ExclusionPolicy::Pairs13;
+
+/// Number of bonds separating `i` and `j` in the molecular topology of
+/// `universe`, found by a breadth-first search over `universe.bonds()`.
+/// Returns `None` if `i` and `j` are not connected within 3 bonds, which
+/// is as far as any `ExclusionPolicy` needs to look.
+fn bond_distance(universe: &Universe, i: usize, j: usize) -> Option<usize> {
+    if i == j {
+        return Some(0);
+    }
+
+    let mut visited = vec![i];
+    let mut queue = VecDeque::new();
+    queue.push_back((i, 0));
+
+    while let Some((current, distance)) = queue.pop_front() {
+        if distance >= 3 {
+            continue;
+        }
+
+        for (a, b, _) in universe.bonds() {
+            let neighbor = if a == current {
+                Some(b)
+            } else if b == current {
+                Some(a)
+            } else {
+                None
+            };
+
+            if let Some(neighbor) = neighbor {
+                if neighbor == j {
+                    return Some(distance + 1);
+                }
+                if !visited.contains(&neighbor) {
+                    visited.push(neighbor);
+                    queue.push_back((neighbor, distance + 1));
+                }
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Should the nonbonded pair `(i, j)` be skipped in favor of the bonded
+/// terms covering it, under `policy`?
+pub fn is_excluded(universe: &Universe, policy: ExclusionPolicy, i: usize, j: usize) -> bool {
+    let max_distance = match policy {
+        ExclusionPolicy::None => return false,
+        ExclusionPolicy::Pairs12 => 1,
+        ExclusionPolicy::Pairs13 => 2,
+        ExclusionPolicy::Pairs14 => 3,
+    };
+
+    match bond_distance(universe, i, j) {
+        Some(distance) => distance <= max_distance,
+        None => false,
+    }
+}
+
+/// Compute the energy of every bond stretch, angle bend and dihedral
+/// torsion in `universe`.
+pub fn bonded_energy(universe: &Universe) -> f64 {
+    let mut energy = 0.0;
+
+    for (i, j, potential) in universe.bonds() {
+        let r = universe.wrap_vector(i, j).norm();
+        energy += potential.energy(r);
+    }
+
+    for (i, j, k, potential) in universe.angles() {
+        let theta = angle(universe, i, j, k);
+        energy += potential.energy(theta);
+    }
+
+    for (i, j, k, l, potential) in universe.dihedrals() {
+        let phi = dihedral(universe, i, j, k, l);
+        energy += potential.energy(phi);
+    }
+
+    return energy;
+}
+
+/// Add the bonded contribution to the per-atom forces already
+/// accumulated in `forces`.
+pub fn bonded_forces(universe: &Universe, forces: &mut [Vector3D]) {
+    for (i, j, potential) in universe.bonds() {
+        let d = universe.wrap_vector(i, j);
+        let dn = d.normalized();
+        let f = potential.force(d.norm());
+        forces[i] = forces[i] + f * dn;
+        forces[j] = forces[j] - f * dn;
+    }
+
+    for (i, j, k, potential) in universe.angles() {
+        let theta = angle(universe, i, j, k);
+        let f = potential.force(theta);
+        distribute_angle_forces(universe, i, j, k, f, forces);
+    }
+
+    for (i, j, k, l, potential) in universe.dihedrals() {
+        let phi = dihedral(universe, i, j, k, l);
+        let f = potential.force(phi);
+        distribute_dihedral_forces(universe, i, j, k, l, f, forces);
+    }
+}
+
+/// Compute the bonded contribution to the virial tensor, summing
+/// `f . r` over every bond vector participating in a bonded term.
+///
+/// Each term's contribution is derived from that term's own isolated
+/// force, computed into a fresh, zeroed force buffer rather than reused
+/// from the shared, globally-accumulated `bonded_forces` result: an
+/// atom taking part in more than one bonded term (the central atom of
+/// an angle, say, which is also in two bonds) must not have its total
+/// force attributed to every term it appears in.
+pub fn bonded_virial(universe: &Universe) -> Matrix3 {
+    let mut virial = Matrix3::zero();
+
+    for (i, j, potential) in universe.bonds() {
+        let d = universe.wrap_vector(i, j);
+        let f = potential.force(d.norm());
+        let dn = d.normalized();
+        // The isolated force on i is `f * dn`, which tensored with
+        // `d = r_i - r_j` (using j as the reference atom) gives the
+        // virial.
+        virial = virial + f * dn.tensorial(&d);
+    }
+
+    for (i, j, k, potential) in universe.angles() {
+        let theta = angle(universe, i, j, k);
+        let f = potential.force(theta);
+
+        let mut local = vec![Vector3D::new(0.0, 0.0, 0.0); universe.size()];
+        distribute_angle_forces(universe, i, j, k, f, &mut local);
+
+        virial = virial + local[i].tensorial(&universe.wrap_vector(i, j));
+        virial = virial + local[k].tensorial(&universe.wrap_vector(k, j));
+    }
+
+    for (i, j, k, l, potential) in universe.dihedrals() {
+        let phi = dihedral(universe, i, j, k, l);
+        let f = potential.force(phi);
+
+        let mut local = vec![Vector3D::new(0.0, 0.0, 0.0); universe.size()];
+        distribute_dihedral_forces(universe, i, j, k, l, f, &mut local);
+
+        // `j` is used as the single reference atom for every
+        // non-central dihedral atom, rather than mixing `j` (for `i`)
+        // and `k` (for `l`) as two different references.
+        virial = virial + local[i].tensorial(&universe.wrap_vector(i, j));
+        virial = virial + local[k].tensorial(&universe.wrap_vector(k, j));
+        virial = virial + local[l].tensorial(&universe.wrap_vector(l, j));
+    }
+
+    return virial;
+}
+
+/// Angle `theta` (in radians) between the bonds `j-i` and `j-k`, with
+/// `j` the central atom.
+fn angle(universe: &Universe, i: usize, j: usize, k: usize) -> f64 {
+    let rji = universe.wrap_vector(j, i);
+    let rjk = universe.wrap_vector(j, k);
+    let cos_theta = (rji * rjk) / (rji.norm() * rjk.norm());
+    return cos_theta.acos();
+}
+
+/// Dihedral angle `phi` (in radians) of the `i-j-k-l` torsion, around
+/// the central `j-k` bond.
+fn dihedral(universe: &Universe, i: usize, j: usize, k: usize, l: usize) -> f64 {
+    let rij = universe.wrap_vector(i, j);
+    let rjk = universe.wrap_vector(j, k);
+    let rkl = universe.wrap_vector(k, l);
+
+    let m = rij ^ rjk;
+    let n = rjk ^ rkl;
+    let cos_phi = (m * n) / (m.norm() * n.norm());
+    return cos_phi.acos();
+}
+
+/// Distribute the angle-bend force factor `f = -dE/dtheta` to the three
+/// atoms `i`, `j` and `k` of the angle, through the chain rule on
+/// `cos(theta)`.
+fn distribute_angle_forces(universe: &Universe, i: usize, j: usize, k: usize, f: f64, forces: &mut [Vector3D]) {
+    let rji = universe.wrap_vector(i, j);
+    let rjk = universe.wrap_vector(k, j);
+    let dji = rji.norm();
+    let djk = rjk.norm();
+    let nji = rji.normalized();
+    let njk = rjk.normalized();
+
+    let cos_theta = (rji * rjk) / (dji * djk);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    if sin_theta < 1e-12 {
+        return;
+    }
+
+    // d(theta)/d(r_i) and d(theta)/d(r_k), projected along the bond
+    // directions.
+    let dtheta_dri = (-1.0 / sin_theta) * (njk - cos_theta * nji) * (1.0 / dji);
+    let dtheta_drk = (-1.0 / sin_theta) * (nji - cos_theta * njk) * (1.0 / djk);
+
+    let force_i = f * dtheta_dri;
+    let force_k = f * dtheta_drk;
+
+    forces[i] = forces[i] + force_i;
+    forces[k] = forces[k] + force_k;
+    forces[j] = forces[j] - force_i - force_k;
+}
+
+/// Distribute the dihedral force factor `f = -dE/dphi` to the four
+/// atoms of the torsion. `i` and `l`, the two ends of the dihedral,
+/// receive the direct contribution from differentiating `phi` with
+/// respect to their own position; `j` and `k` receive the rest of the
+/// gradient, projected onto the central `j-k` bond through
+/// `(rij.rkj)/|rkj|^2` and `(rkl.rkj)/|rkj|^2` (Bekker's formula), so
+/// that the whole set is the true gradient of `phi`, not merely a
+/// force- and torque-free split of it. As `dihedral` returns the
+/// unsigned angle, the forces carry the sign of `rij.n`.
+fn distribute_dihedral_forces(universe: &Universe, i: usize, j: usize, k: usize, l: usize, f: f64, forces: &mut [Vector3D]) {
+    let rij = universe.wrap_vector(i, j);
+    let rjk = universe.wrap_vector(j, k);
+    let rkl = universe.wrap_vector(k, l);
+    let rkj = universe.wrap_vector(k, j);
+
+    let m = rij ^ rjk;
+    let n = rjk ^ rkl;
+    let djk = rjk.norm();
+    let sign = if rij * n < 0.0 { -1.0 } else { 1.0 };
+
+    let force_i = sign * f * djk / m.norm2() * m;
+    let force_l = -sign * f * djk / n.norm2() * n;
+
+    let p = (rij * rkj) / rkj.norm2();
+    let q = (rkl * rkj) / rkj.norm2();
+
+    forces[i] = forces[i] + force_i;
+    forces[l] = forces[l] + force_l;
+    forces[j] = forces[j] + (p - 1.0) * force_i - q * force_l;
+    forces[k] = forces[k] - p * force_i + (q - 1.0) * force_l;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::universe::{Universe, Particle, UnitCell};
+
+    const EPS: f64 = 1e-6;
+
+    struct HarmonicAngle { k: f64, theta0: f64 }
+    impl AnglePotential for HarmonicAngle {
+        fn energy(&self, theta: f64) -> f64 { 0.5 * self.k * (theta - self.theta0).powi(2) }
+        fn force(&self, theta: f64) -> f64 { -self.k * (theta - self.theta0) }
+    }
+
+    struct HarmonicDihedral { k: f64, phi0: f64 }
+    impl DihedralPotential for HarmonicDihedral {
+        fn energy(&self, phi: f64) -> f64 { 0.5 * self.k * (phi - self.phi0).powi(2) }
+        fn force(&self, phi: f64) -> f64 { -self.k * (phi - self.phi0) }
+    }
+
+    fn angle_base_positions() -> [Vector3D; 3] {
+        [
+            Vector3D::new(1.0, 0.0, 0.0),
+            Vector3D::new(0.0, 0.0, 0.0),
+            Vector3D::new(0.3, 1.0, 0.0),
+        ]
+    }
+
+    fn angle_universe(offsets: &[Vector3D; 3]) -> Universe {
+        let mut universe = Universe::from_cell(UnitCell::cubic(20.0));
+        let base = angle_base_positions();
+        for idx in 0..3 {
+            universe.add_particle(Particle::new("F"));
+            universe[idx].set_position(base[idx] + offsets[idx]);
+        }
+        universe.add_angle(0, 1, 2);
+        universe.add_angle_interaction("F", "F", "F",
+            HarmonicAngle{k: 100.0, theta0: ::std::f64::consts::FRAC_PI_2});
+        return universe;
+    }
+
+    fn dihedral_base_positions() -> [Vector3D; 4] {
+        [
+            Vector3D::new(1.0, 0.0, 1.0),
+            Vector3D::new(0.0, 0.0, 0.0),
+            Vector3D::new(0.0, 1.0, 0.0),
+            Vector3D::new(-0.5, 2.0, 0.7),
+        ]
+    }
+
+    fn dihedral_universe(offsets: &[Vector3D; 4]) -> Universe {
+        let mut universe = Universe::from_cell(UnitCell::cubic(20.0));
+        let base = dihedral_base_positions();
+        for idx in 0..4 {
+            universe.add_particle(Particle::new("F"));
+            universe[idx].set_position(base[idx] + offsets[idx]);
+        }
+        universe.add_dihedral(0, 1, 2, 3);
+        universe.add_dihedral_interaction("F", "F", "F", "F",
+            HarmonicDihedral{k: 50.0, phi0: 1.0});
+        return universe;
+    }
+
+    // Central finite-difference gradient of `bonded_energy` with respect
+    // to one coordinate of one atom, used to check that `bonded_forces`
+    // returns the true negative gradient and not merely a force- and
+    // torque-balanced guess.
+    fn numerical_force<F>(natoms: usize, build: F, atom: usize) -> Vector3D
+        where F: Fn(&[Vector3D]) -> Universe
+    {
+        let h = 1e-6;
+        let zero = vec![Vector3D::new(0.0, 0.0, 0.0); natoms];
+        let mut gradient = Vector3D::new(0.0, 0.0, 0.0);
+
+        for axis in 0..3 {
+            let delta = match axis {
+                0 => Vector3D::new(h, 0.0, 0.0),
+                1 => Vector3D::new(0.0, h, 0.0),
+                _ => Vector3D::new(0.0, 0.0, h),
+            };
+
+            let mut plus = zero.clone();
+            plus[atom] = delta;
+            let mut minus = zero.clone();
+            minus[atom] = -1.0 * delta;
+
+            let e_plus = bonded_energy(&build(&plus));
+            let e_minus = bonded_energy(&build(&minus));
+            let d = (e_plus - e_minus) / (2.0 * h);
+
+            match axis {
+                0 => gradient.x = d,
+                1 => gradient.y = d,
+                _ => gradient.z = d,
+            }
+        }
+
+        return -1.0 * gradient;
+    }
+
+    #[test]
+    fn angle_forces_match_finite_difference_gradient() {
+        let zero = [Vector3D::new(0.0, 0.0, 0.0); 3];
+        let universe = angle_universe(&zero);
+        let mut forces = vec![Vector3D::new(0.0, 0.0, 0.0); universe.size()];
+        bonded_forces(&universe, &mut forces);
+
+        for atom in 0..3 {
+            let expected = numerical_force(3, |offsets| {
+                let mut array = [Vector3D::new(0.0, 0.0, 0.0); 3];
+                array.copy_from_slice(offsets);
+                angle_universe(&array)
+            }, atom);
+
+            assert_approx_eq!(forces[atom].x, expected.x, EPS);
+            assert_approx_eq!(forces[atom].y, expected.y, EPS);
+            assert_approx_eq!(forces[atom].z, expected.z, EPS);
+        }
+    }
+
+    #[test]
+    fn dihedral_forces_match_finite_difference_gradient() {
+        let zero = [Vector3D::new(0.0, 0.0, 0.0); 4];
+        let universe = dihedral_universe(&zero);
+        let mut forces = vec![Vector3D::new(0.0, 0.0, 0.0); universe.size()];
+        bonded_forces(&universe, &mut forces);
+
+        for atom in 0..4 {
+            let expected = numerical_force(4, |offsets| {
+                let mut array = [Vector3D::new(0.0, 0.0, 0.0); 4];
+                array.copy_from_slice(offsets);
+                dihedral_universe(&array)
+            }, atom);
+
+            assert_approx_eq!(forces[atom].x, expected.x, EPS);
+            assert_approx_eq!(forces[atom].y, expected.y, EPS);
+            assert_approx_eq!(forces[atom].z, expected.z, EPS);
+        }
+    }
+
+    #[test]
+    fn exclusion_policy_respects_bond_distance() {
+        use ::potentials::Harmonic;
+        use ::units;
+
+        // A linear chain 0-1-2-3, so that 0-1 is a 1-2 pair, 0-2 is a
+        // 1-3 pair, and 0-3 is a 1-4 pair.
+        let mut universe = Universe::from_cell(UnitCell::cubic(20.0));
+        for i in 0..4 {
+            universe.add_particle(Particle::new("F"));
+            universe[i].set_position(Vector3D::new(i as f64, 0.0, 0.0));
+        }
+
+        universe.add_bond(0, 1);
+        universe.add_bond(1, 2);
+        universe.add_bond(2, 3);
+        universe.add_bond_interaction("F", "F",
+            Harmonic{k: units::from(300.0, "kJ/mol/A^2").unwrap(), x0: units::from(1.0, "A").unwrap()});
+
+        assert!(is_excluded(&universe, ExclusionPolicy::Pairs12, 0, 1));
+        assert!(!is_excluded(&universe, ExclusionPolicy::Pairs12, 0, 2));
+
+        assert!(is_excluded(&universe, ExclusionPolicy::Pairs13, 0, 2));
+        assert!(!is_excluded(&universe, ExclusionPolicy::Pairs13, 0, 3));
+
+        assert!(is_excluded(&universe, ExclusionPolicy::Pairs14, 0, 3));
+
+        assert!(!is_excluded(&universe, ExclusionPolicy::None, 0, 1));
+    }
+
+    // A chain 0-1-2-3 carrying a bond, an angle and a dihedral term at
+    // once, so that atoms 1 and 2 are each shared by more than one
+    // bonded term -- the central atom of the angle is also in two
+    // bonds, and the dihedral spans every atom in the chain. This is
+    // exactly the topology that hid the `bonded_virial` bug: reusing
+    // the globally-accumulated `bonded_forces` result inside each
+    // term's virial folded every other term's force on a shared atom
+    // into this term's contribution too.
+    fn chain_universe() -> Universe {
+        use ::potentials::Harmonic;
+        use ::units;
+
+        let base = dihedral_base_positions();
+        let mut universe = Universe::from_cell(UnitCell::cubic(20.0));
+        for idx in 0..4 {
+            universe.add_particle(Particle::new("F"));
+            universe[idx].set_position(base[idx]);
+        }
+
+        universe.add_bond(0, 1);
+        universe.add_bond(1, 2);
+        universe.add_bond(2, 3);
+        universe.add_bond_interaction("F", "F",
+            Harmonic{k: units::from(300.0, "kJ/mol/A^2").unwrap(), x0: units::from(1.0, "A").unwrap()});
+
+        universe.add_angle(0, 1, 2);
+        universe.add_angle_interaction("F", "F", "F",
+            HarmonicAngle{k: 100.0, theta0: ::std::f64::consts::FRAC_PI_2});
+
+        universe.add_dihedral(0, 1, 2, 3);
+        universe.add_dihedral_interaction("F", "F", "F", "F",
+            HarmonicDihedral{k: 50.0, phi0: 1.0});
+
+        return universe;
+    }
+
+    #[test]
+    fn virial_matches_total_force_dotted_with_absolute_position() {
+        // Since every bonded term's isolated force sums to zero over
+        // its own atoms, `bonded_virial` (which sums each term's own
+        // isolated force against *relative* bond vectors) must equal
+        // `sum_atom bonded_forces[atom] (x) position[atom]`, computed
+        // here from the *globally-accumulated* per-atom force and
+        // *absolute* positions instead -- a completely different code
+        // path that only agrees with `bonded_virial` if each term's
+        // contribution was correctly isolated before being summed.
+        let universe = chain_universe();
+
+        let mut forces = vec![Vector3D::new(0.0, 0.0, 0.0); universe.size()];
+        bonded_forces(&universe, &mut forces);
+
+        let mut expected = Matrix3::zero();
+        for i in 0..universe.size() {
+            expected = expected + forces[i].tensorial(&universe[i].position());
+        }
+
+        let virial = bonded_virial(&universe);
+        for row in 0..3 {
+            for col in 0..3 {
+                assert_approx_eq!(virial[(row, col)], expected[(row, col)], EPS);
+            }
+        }
+    }
+}
@@ -11,6 +11,9 @@ use ::constants::K_BOLTZMANN;
 use ::types::*;
 use ::universe::Universe;
 
+use super::threebody;
+use super::bonded;
+
 /// The compute trait allow to compute properties of an universe, whithout
 /// modifying this universe. The Output type is the type of the computed
 /// property.
@@ -32,17 +35,26 @@ impl Compute for Forces {
             res.push(Vector3D::new(0.0, 0.0, 0.0));
         }
 
-        for i in 0..universe.size() {
-            for j in (i+1)..universe.size() {
-                for potential in universe.pairs(i, j) {
-                    let d = universe.wrap_vector(i, j);
-                    let dn = d.normalized();
-                    let f = potential.force(d.norm());
-                    res[i] = res[i] + f * dn;
-                    res[j] = res[j] - f * dn;
-                }
+        // `universe.neighbors()` hands back the `NeighborList` owned by
+        // `universe` itself, rebuilding it only when the Verlet skin is
+        // exceeded (or on every call, for small or non-periodic systems),
+        // rather than a fresh one that would never survive long enough
+        // for that caching to matter.
+        let mut neighbors = universe.neighbors(universe.max_cutoff());
+        for &(i, j) in neighbors.pairs(universe) {
+            if bonded::is_excluded(universe, bonded::DEFAULT_EXCLUSION_POLICY, i, j) {
+                continue;
+            }
+            for potential in universe.pairs(i, j) {
+                let d = universe.wrap_vector(i, j);
+                let dn = d.normalized();
+                let f = potential.force(d.norm());
+                res[i] = res[i] + f * dn;
+                res[j] = res[j] - f * dn;
             }
         }
+        threebody::three_body_forces(universe, &mut res);
+        bonded::bonded_forces(universe, &mut res);
         return res;
     }
 }
@@ -54,14 +66,18 @@ impl Compute for PotentialEnergy {
     type Output = f64;
     fn compute(&self, universe: &Universe) -> f64 {
         let mut res = 0.0;
-        for i in 0..universe.size() {
-            for j in (i+1)..universe.size() {
-                for potential in universe.pairs(i, j) {
-                    let d = universe.wrap_vector(i, j);
-                    res += potential.energy(d.norm());
-                }
+        let mut neighbors = universe.neighbors(universe.max_cutoff());
+        for &(i, j) in neighbors.pairs(universe) {
+            if bonded::is_excluded(universe, bonded::DEFAULT_EXCLUSION_POLICY, i, j) {
+                continue;
+            }
+            for potential in universe.pairs(i, j) {
+                let d = universe.wrap_vector(i, j);
+                res += potential.energy(d.norm());
             }
         }
+        res += threebody::three_body_energy(universe);
+        res += bonded::bonded_energy(universe);
         return res;
     }
 }
@@ -122,14 +138,20 @@ impl Compute for Virial {
     type Output = Matrix3;
     fn compute(&self, universe: &Universe) -> Matrix3 {
         let mut res = Matrix3::zero();
-        for i in 0..universe.size() {
-            for j in (i+1)..universe.size() {
-                for potential in universe.pairs(i, j) {
-                    let d = universe.wrap_vector(i, j);
-                    res = res + 2.0 * potential.virial(&d);
-                }
+        let mut neighbors = universe.neighbors(universe.max_cutoff());
+        for &(i, j) in neighbors.pairs(universe) {
+            if bonded::is_excluded(universe, bonded::DEFAULT_EXCLUSION_POLICY, i, j) {
+                continue;
+            }
+            for potential in universe.pairs(i, j) {
+                let d = universe.wrap_vector(i, j);
+                res = res + 2.0 * potential.virial(&d);
             }
         }
+        // Pair terms above count `2 * f (x) r` for every pair; the
+        // three-body and bonded `sum f (x) r` are scaled the same way.
+        res = res + 2.0 * threebody::three_body_virial(universe);
+        res = res + 2.0 * bonded::bonded_virial(universe);
         return res;
     }
 }
@@ -164,17 +186,31 @@ impl Compute for Stress {
 }
 
 /******************************************************************************/
-/// Compute the virial pressure of the system
+/// Compute the full kinetic+virial pressure tensor of the system,
+/// defined as `P = (1/V)*(sum_i m_i*v_i⊗v_i - W)`, where the kinetic
+/// part is the same dyadic already assembled by `Stress`, and `W` is
+/// the virial tensor accumulated by `Virial`; this is the same tensor
+/// as `Stress`. Unlike the
+/// scalar `Pressure`, the anisotropic and off-diagonal components of
+/// this tensor are directly observable, which matters for NPT
+/// simulations with non-cubic cells and for measuring surface tension.
+pub struct PressureTensor;
+impl Compute for PressureTensor {
+    type Output = Matrix3;
+    fn compute(&self, universe: &Universe) -> Matrix3 {
+        return Stress.compute(universe);
+    }
+}
+
+/******************************************************************************/
+/// Compute the virial pressure of the system, defined as one third of
+/// the trace of the `PressureTensor`.
 pub struct Pressure;
 impl Compute for Pressure {
     type Output = f64;
     fn compute(&self, universe: &Universe) -> f64 {
-        let W = Virial.compute(universe);
-        let virial = W[(0, 0)] + W[(1, 1)] + W[(2, 2)];
-        let V = Volume.compute(universe);
-        let natoms = universe.size() as f64;
-        let T = Temperature.compute(universe);
-        return natoms * K_BOLTZMANN * T / V - virial / (3.0 * V);
+        let P = PressureTensor.compute(universe);
+        return (P[(0, 0)] + P[(1, 1)] + P[(2, 2)]) / 3.0;
     }
 }
 
@@ -289,4 +325,16 @@ mod test {
         assert_approx_eq!(P, units::from(514.5790116223092, "bar").unwrap(), 1e-9);
         assert_eq!(P, universe.pressure());
     }
+
+    #[test]
+    fn pressure_tensor() {
+        let universe = &testing_universe();
+        let tensor = PressureTensor.compute(universe);
+        let P = Pressure.compute(universe);
+
+        let trace = (tensor[(0, 0)] + tensor[(1, 1)] + tensor[(2, 2)]) / 3.0;
+        assert_approx_eq!(trace, P, 1e-9);
+        assert_eq!(tensor, Stress.compute(universe));
+        assert_eq!(tensor, universe.pressure_tensor());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,25 @@
+/*
+ * Cymbalum, Molecular Simulation in Rust
+ * Copyright (C) 2015 Guillaume Fraux
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/
+*/
+
+//! The `simulation` module contains the `Compute` trait and the
+//! properties that can be computed from an `Universe`, as well as the
+//! supporting machinery (neighbor lists, ...) used to compute them
+//! efficiently.
+
+mod compute;
+mod neighbors;
+mod threebody;
+mod barostat;
+mod bonded;
+
+pub use self::compute::*;
+pub use self::neighbors::{CellList, NeighborList};
+pub use self::threebody::ThreeBody;
+pub use self::barostat::{Berendsen, AnisotropicBerendsen};
+pub use self::bonded::{AnglePotential, DihedralPotential, ExclusionPolicy};
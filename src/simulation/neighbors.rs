@@ -0,0 +1,240 @@
+/*
+ * Cymbalum, Molecular Simulation in Rust
+ * Copyright (C) 2015 Guillaume Fraux
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/
+*/
+
+//! Cell lists and Verlet neighbor lists.
+//!
+//! Looping over every `i < j` pair of particles is O(N^2), and dominates
+//! the cost of the `Forces`/`PotentialEnergy`/`Virial` computes for large
+//! systems. This module provides a way to only consider pairs that are
+//! close enough to interact, by binning particles into a grid of cells
+//! sized on the interaction cutoff.
+
+use std::collections::{HashMap, HashSet};
+
+use ::types::*;
+use ::universe::Universe;
+
+/// Extra distance added to the interaction cutoff when building the
+/// neighbor list. A particle can move by up to half of this skin before
+/// a pair that should be in the list could have been missed, at which
+/// point the list must be rebuilt (the Verlet-list invariant).
+const SKIN: f64 = 2.0;
+
+/// Below this number of particles, walking the full `i < j` double loop
+/// is cheaper than building and walking a cell list.
+const MIN_PARTICLES_FOR_CELL_LIST: usize = 100;
+
+/// A `CellList` partitions a `UnitCell` into a grid of cells whose edge
+/// length is at least `cutoff`, bins every particle into its owning cell
+/// from its wrapped position, and yields candidate `(i, j)` pairs by
+/// only examining the 27 neighboring cells (including the cell itself)
+/// of each occupied cell.
+pub struct CellList {
+    cutoff: f64,
+    dimensions: (isize, isize, isize),
+    cells: HashMap<(isize, isize, isize), Vec<usize>>,
+}
+
+impl CellList {
+    /// Build a new cell list for `universe`, binning particles into
+    /// cells of at least `cutoff` in size.
+    pub fn new(universe: &Universe, cutoff: f64) -> CellList {
+        let cell = universe.cell();
+        let dimensions = (
+            ::std::cmp::max(1, (cell.a() / cutoff).floor() as isize),
+            ::std::cmp::max(1, (cell.b() / cutoff).floor() as isize),
+            ::std::cmp::max(1, (cell.c() / cutoff).floor() as isize),
+        );
+
+        let mut cells: HashMap<(isize, isize, isize), Vec<usize>> = HashMap::new();
+        for (i, particle) in universe.iter().enumerate() {
+            let fractional = cell.fractional(&particle.position());
+            let index = (
+                wrapped_cell_index(fractional.x, dimensions.0),
+                wrapped_cell_index(fractional.y, dimensions.1),
+                wrapped_cell_index(fractional.z, dimensions.2),
+            );
+            cells.entry(index).or_insert_with(Vec::new).push(i);
+        }
+
+        CellList { cutoff: cutoff, dimensions: dimensions, cells: cells }
+    }
+
+    /// Get all the candidate `(i, j)` pairs with `i < j`, restricted to
+    /// particles whose cells are within one cell of each other.
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        for (&(x, y, z), indexes) in &self.cells {
+            // When a grid axis has fewer than 3 cells, several of the 27
+            // offsets below wrap around to the very same neighboring
+            // cell (e.g. a single cell is its own neighbor 27 times
+            // over). Only visit each distinct neighboring cell once per
+            // source cell, or the same pair would be counted several
+            // times.
+            let mut visited = HashSet::new();
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        let neighbor = (
+                            wrap(x + dx, self.dimensions.0),
+                            wrap(y + dy, self.dimensions.1),
+                            wrap(z + dz, self.dimensions.2),
+                        );
+
+                        if !visited.insert(neighbor) {
+                            continue;
+                        }
+
+                        if let Some(others) = self.cells.get(&neighbor) {
+                            for &i in indexes {
+                                for &j in others {
+                                    if i < j {
+                                        pairs.push((i, j));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        return pairs;
+    }
+}
+
+fn wrapped_cell_index(fractional: f64, size: isize) -> isize {
+    let wrapped = fractional - fractional.floor();
+    wrap((wrapped * size as f64) as isize, size)
+}
+
+fn wrap(index: isize, size: isize) -> isize {
+    ((index % size) + size) % size
+}
+
+/// A `NeighborList` caches the candidate pairs built by a [`CellList`]
+/// and tracks the largest displacement of any particle since the list
+/// was last built. Following the usual Verlet-list invariant, the list
+/// only needs to be rebuilt once twice that displacement exceeds the
+/// skin distance, since that is the only way a pair could have entered
+/// the cutoff without being in the cached list.
+///
+/// This caching only pays off if the same `NeighborList` survives
+/// across calls: `Universe` owns one behind its `neighbors()` accessor,
+/// shared by `Forces`, `PotentialEnergy` and `Virial` for a given
+/// simulation step, rather than each compute building its own from
+/// scratch and never actually triggering the skin/rebuild logic above.
+///
+/// [`CellList`]: struct.CellList.html
+pub struct NeighborList {
+    cutoff: f64,
+    skin: f64,
+    pairs: Vec<(usize, usize)>,
+    reference: Vec<Vector3D>,
+}
+
+impl NeighborList {
+    /// Create a new, empty `NeighborList` for a cutoff of `cutoff`.
+    pub fn new(cutoff: f64) -> NeighborList {
+        NeighborList {
+            cutoff: cutoff,
+            skin: SKIN,
+            pairs: Vec::new(),
+            reference: Vec::new(),
+        }
+    }
+
+    /// Get the candidate pairs for `universe`, rebuilding the underlying
+    /// cell list first if needed. For small systems, or for systems
+    /// without a finite cell, this falls back to the brute-force list of
+    /// every `i < j` pair.
+    pub fn pairs(&mut self, universe: &Universe) -> &[(usize, usize)] {
+        if self.needs_rebuild(universe) {
+            self.rebuild(universe);
+        }
+        return &self.pairs;
+    }
+
+    fn needs_rebuild(&self, universe: &Universe) -> bool {
+        if self.reference.len() != universe.size() {
+            return true;
+        }
+
+        if universe.size() < MIN_PARTICLES_FOR_CELL_LIST || !universe.cell().volume().is_finite() {
+            return true;
+        }
+
+        let mut max_displacement = 0.0;
+        for (i, particle) in universe.iter().enumerate() {
+            let displacement = (particle.position() - self.reference[i]).norm();
+            if displacement > max_displacement {
+                max_displacement = displacement;
+            }
+        }
+        return 2.0 * max_displacement > self.skin;
+    }
+
+    fn rebuild(&mut self, universe: &Universe) {
+        self.reference = universe.iter().map(|particle| particle.position()).collect();
+
+        if universe.size() < MIN_PARTICLES_FOR_CELL_LIST || !universe.cell().volume().is_finite() {
+            self.pairs = brute_force_pairs(universe.size());
+        } else {
+            self.pairs = CellList::new(universe, self.cutoff + self.skin).pairs();
+        }
+    }
+}
+
+fn brute_force_pairs(size: usize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::with_capacity(size * (size - 1) / 2);
+    for i in 0..size {
+        for j in (i + 1)..size {
+            pairs.push((i, j));
+        }
+    }
+    return pairs;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::universe::{Universe, Particle, UnitCell};
+
+    // A box small enough that the cutoff collapses the grid down to a
+    // single cell along every axis: this is exactly the regime where
+    // naively visiting all 27 neighbor offsets used to revisit the same
+    // cell several times over.
+    fn tiny_periodic_universe() -> Universe {
+        let mut universe = Universe::from_cell(UnitCell::cubic(2.0));
+
+        universe.add_particle(Particle::new("F"));
+        universe[0].set_position(Vector3D::new(0.0, 0.0, 0.0));
+
+        universe.add_particle(Particle::new("F"));
+        universe[1].set_position(Vector3D::new(1.0, 0.0, 0.0));
+
+        return universe;
+    }
+
+    #[test]
+    fn single_cell_grid_does_not_duplicate_pairs() {
+        let universe = tiny_periodic_universe();
+        // A cutoff bigger than the box collapses dimensions to (1, 1, 1).
+        let cells = CellList::new(&universe, 5.0);
+        assert_eq!(cells.pairs(), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn two_cell_grid_does_not_duplicate_pairs() {
+        let universe = tiny_periodic_universe();
+        // A cutoff of 1.0 in a box of side 2.0 gives dimensions (2, 2, 2),
+        // where offsets of -1 and +1 along an axis wrap to the same cell.
+        let cells = CellList::new(&universe, 1.0);
+        assert_eq!(cells.pairs(), vec![(0, 1)]);
+    }
+}